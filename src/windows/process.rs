@@ -1,39 +1,231 @@
-// 
+//
 // Sysinfo
-// 
+//
 // Copyright (c) 2018 Guillaume Gomez
 //
 
+use core::ffi::c_void;
 use std::mem::{size_of, zeroed};
 use std::fmt::{self, Formatter, Debug};
-use std::str;
+use std::sync::Arc;
 
-use libc::{c_uint, c_void, memcpy};
+use chrono::{DateTime, Local, TimeZone};
 
 use Pid;
 use ProcessExt;
 
-use winapi::shared::minwindef::{DWORD, FALSE, FILETIME, MAX_PATH/*, TRUE, USHORT*/};
-use winapi::um::handleapi::CloseHandle;
-use winapi::um::winnt::{
-    HANDLE, ULARGE_INTEGER, /*THREAD_GET_CONTEXT, THREAD_QUERY_INFORMATION, THREAD_SUSPEND_RESUME,*/
-    /*, PWSTR*/ PROCESS_QUERY_INFORMATION, PROCESS_TERMINATE, PROCESS_VM_READ,
-};
-use winapi::um::processthreadsapi::{GetProcessTimes, OpenProcess, TerminateProcess};
-use winapi::um::psapi::{
-    GetProcessMemoryInfo, PROCESS_MEMORY_COUNTERS, PROCESS_MEMORY_COUNTERS_EX,
-    EnumProcessModulesEx, GetModuleBaseNameW, LIST_MODULES_ALL,
+use windows::core::PWSTR;
+use windows::Win32::Foundation::{
+    CloseHandle, FALSE, FILETIME, HANDLE, HMODULE, MAX_PATH, STATUS_BUFFER_OVERFLOW,
+    STATUS_INFO_LENGTH_MISMATCH, STATUS_SUCCESS, STILL_ACTIVE, UNICODE_STRING,
 };
-use winapi::um::sysinfoapi::GetSystemTimeAsFileTime;
-use winapi::um::tlhelp32::{
+use windows::Win32::System::Diagnostics::Debug::ReadProcessMemory;
+use windows::Win32::System::Diagnostics::ToolHelp::{
     CreateToolhelp32Snapshot, Process32First, Process32Next, PROCESSENTRY32, TH32CS_SNAPPROCESS,
 };
+use windows::Win32::System::ProcessStatus::{
+    K32EnumProcessModulesEx, K32GetModuleBaseNameW, K32GetProcessMemoryInfo, LIST_MODULES_ALL,
+    PROCESS_MEMORY_COUNTERS, PROCESS_MEMORY_COUNTERS_EX,
+};
+use windows::Win32::System::SystemInformation::GetSystemTimeAsFileTime;
+use windows::Win32::System::Threading::{
+    GetCurrentProcessId, GetExitCodeProcess, GetPriorityClass, GetProcessTimes, OpenProcess,
+    TerminateProcess, ABOVE_NORMAL_PRIORITY_CLASS, BELOW_NORMAL_PRIORITY_CLASS, HIGH_PRIORITY_CLASS,
+    IDLE_PRIORITY_CLASS, NORMAL_PRIORITY_CLASS, PROCESS_CREATION_FLAGS, PROCESS_QUERY_INFORMATION,
+    PROCESS_TERMINATE, PROCESS_VM_READ, REALTIME_PRIORITY_CLASS,
+};
+
+/// Undocumented native structures and entry points needed to walk another
+/// process' PEB. These mirror the layouts used by `ntdll.dll`; fields we never
+/// read are declared as well, so that the offsets are correct.
+#[allow(non_snake_case, non_camel_case_types, dead_code)]
+mod ffi {
+    use core::ffi::c_void;
+    use windows::Win32::Foundation::{HANDLE, NTSTATUS, UNICODE_STRING};
+
+    // `PROCESSINFOCLASS` values we query.
+    pub const PROCESS_BASIC_INFORMATION: u32 = 0;
+    pub const PROCESS_WOW64_INFORMATION: u32 = 26;
+    pub const PROCESS_COMMAND_LINE_INFORMATION: u32 = 60;
+
+    #[repr(C)]
+    pub struct STRING {
+        pub Length: u16,
+        pub MaximumLength: u16,
+        pub Buffer: *mut i8,
+    }
+
+    #[repr(C)]
+    pub struct CURDIR {
+        pub DosPath: UNICODE_STRING,
+        pub Handle: *mut c_void,
+    }
+
+    #[repr(C)]
+    pub struct RTL_DRIVE_LETTER_CURDIR {
+        pub Flags: u16,
+        pub Length: u16,
+        pub TimeStamp: u32,
+        pub DosPath: STRING,
+    }
+
+    #[repr(C)]
+    pub struct PROCESS_BASIC_INFORMATION {
+        pub ExitStatus: NTSTATUS,
+        pub PebBaseAddress: *mut PEB,
+        pub AffinityMask: usize,
+        pub BasePriority: i32,
+        pub UniqueProcessId: usize,
+        pub InheritedFromUniqueProcessId: usize,
+    }
+
+    // Pointer-typed fields make this layout correct for both 32 and 64 bit
+    // builds: `ProcessParameters` lands at offset 0x10 / 0x20 respectively.
+    #[repr(C)]
+    pub struct PEB {
+        pub Reserved1: [u8; 2],
+        pub BeingDebugged: u8,
+        pub BitField: u8,
+        pub Mutant: *mut c_void,
+        pub ImageBaseAddress: *mut c_void,
+        pub Ldr: *mut c_void,
+        pub ProcessParameters: *mut RTL_USER_PROCESS_PARAMETERS,
+    }
+
+    #[repr(C)]
+    pub struct RTL_USER_PROCESS_PARAMETERS {
+        pub MaximumLength: u32,
+        pub Length: u32,
+        pub Flags: u32,
+        pub DebugFlags: u32,
+        pub ConsoleHandle: *mut c_void,
+        pub ConsoleFlags: u32,
+        pub StandardInput: *mut c_void,
+        pub StandardOutput: *mut c_void,
+        pub StandardError: *mut c_void,
+        pub CurrentDirectory: CURDIR,
+        pub DllPath: UNICODE_STRING,
+        pub ImagePathName: UNICODE_STRING,
+        pub CommandLine: UNICODE_STRING,
+        pub Environment: *mut c_void,
+        pub StartingX: u32,
+        pub StartingY: u32,
+        pub CountX: u32,
+        pub CountY: u32,
+        pub CountCharsX: u32,
+        pub CountCharsY: u32,
+        pub FillAttribute: u32,
+        pub WindowFlags: u32,
+        pub ShowWindowFlags: u32,
+        pub WindowTitle: UNICODE_STRING,
+        pub DesktopInfo: UNICODE_STRING,
+        pub ShellInfo: UNICODE_STRING,
+        pub RuntimeData: UNICODE_STRING,
+        pub CurrentDirectories: [RTL_DRIVE_LETTER_CURDIR; 32],
+        pub EnvironmentSize: usize,
+        pub EnvironmentVersion: usize,
+    }
+
+    // --- 32-bit (WOW64) mirrors of the structures above. A process running
+    // under WOW64 stores 32-bit pointers in its PEB, so on a 64-bit host we
+    // must read it through these layouts instead of the native ones.
+
+    #[repr(C)]
+    pub struct UNICODE_STRING32 {
+        pub Length: u16,
+        pub MaximumLength: u16,
+        pub Buffer: u32,
+    }
+
+    #[repr(C)]
+    pub struct STRING32 {
+        pub Length: u16,
+        pub MaximumLength: u16,
+        pub Buffer: u32,
+    }
+
+    #[repr(C)]
+    pub struct CURDIR32 {
+        pub DosPath: UNICODE_STRING32,
+        pub Handle: u32,
+    }
+
+    #[repr(C)]
+    pub struct RTL_DRIVE_LETTER_CURDIR32 {
+        pub Flags: u16,
+        pub Length: u16,
+        pub TimeStamp: u32,
+        pub DosPath: STRING32,
+    }
+
+    #[repr(C)]
+    pub struct PEB32 {
+        pub Reserved1: [u8; 2],
+        pub BeingDebugged: u8,
+        pub BitField: u8,
+        pub Mutant: u32,
+        pub ImageBaseAddress: u32,
+        pub Ldr: u32,
+        pub ProcessParameters: u32,
+    }
+
+    #[repr(C)]
+    pub struct RTL_USER_PROCESS_PARAMETERS32 {
+        pub MaximumLength: u32,
+        pub Length: u32,
+        pub Flags: u32,
+        pub DebugFlags: u32,
+        pub ConsoleHandle: u32,
+        pub ConsoleFlags: u32,
+        pub StandardInput: u32,
+        pub StandardOutput: u32,
+        pub StandardError: u32,
+        pub CurrentDirectory: CURDIR32,
+        pub DllPath: UNICODE_STRING32,
+        pub ImagePathName: UNICODE_STRING32,
+        pub CommandLine: UNICODE_STRING32,
+        pub Environment: u32,
+        pub StartingX: u32,
+        pub StartingY: u32,
+        pub CountX: u32,
+        pub CountY: u32,
+        pub CountCharsX: u32,
+        pub CountCharsY: u32,
+        pub FillAttribute: u32,
+        pub WindowFlags: u32,
+        pub ShowWindowFlags: u32,
+        pub WindowTitle: UNICODE_STRING32,
+        pub DesktopInfo: UNICODE_STRING32,
+        pub ShellInfo: UNICODE_STRING32,
+        pub RuntimeData: UNICODE_STRING32,
+        pub CurrentDirectories: [RTL_DRIVE_LETTER_CURDIR32; 32],
+        pub EnvironmentSize: u32,
+        pub EnvironmentVersion: u32,
+    }
+
+    #[link(name = "ntdll")]
+    extern "system" {
+        pub fn NtQueryInformationProcess(
+            ProcessHandle: HANDLE,
+            ProcessInformationClass: u32,
+            ProcessInformation: *mut c_void,
+            ProcessInformationLength: u32,
+            ReturnLength: *mut u32,
+        ) -> NTSTATUS;
+    }
+}
 
 /// Enum describing the different status of a process.
 #[derive(Clone, Debug)]
 pub enum ProcessStatus {
     /// Currently runnable.
     Run,
+    /// Has exited but is still referenced by an open handle.
+    Zombie,
+    /// Terminated and no longer accessible.
+    Dead,
+    /// Status could not be determined.
+    Unknown,
 }
 
 impl ProcessStatus {
@@ -41,6 +233,9 @@ impl ProcessStatus {
     pub fn to_string(&self) -> &str {
         match *self {
             ProcessStatus::Run => "Runnable",
+            ProcessStatus::Zombie => "Zombie",
+            ProcessStatus::Dead => "Dead",
+            ProcessStatus::Unknown => "Unknown",
         }
     }
 }
@@ -51,23 +246,62 @@ impl fmt::Display for ProcessStatus {
     }
 }
 
-fn get_process_handler(pid: Pid) -> Option<HANDLE> {
+/// Scheduling priority class a process runs at.
+#[derive(Clone, Copy, Debug)]
+pub enum ProcessPriority {
+    /// `IDLE_PRIORITY_CLASS`
+    Idle,
+    /// `BELOW_NORMAL_PRIORITY_CLASS`
+    BelowNormal,
+    /// `NORMAL_PRIORITY_CLASS`
+    Normal,
+    /// `ABOVE_NORMAL_PRIORITY_CLASS`
+    AboveNormal,
+    /// `HIGH_PRIORITY_CLASS`
+    High,
+    /// `REALTIME_PRIORITY_CLASS`
+    RealTime,
+    /// Priority class could not be determined.
+    Unknown,
+}
+
+/// Closes its wrapped `HANDLE` when dropped.
+struct HandleInner(HANDLE);
+
+impl Drop for HandleInner {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = CloseHandle(self.0);
+        }
+    }
+}
+
+/// Reference-counted owner of a process `HANDLE`. Cloning a `Process` shares
+/// the same handle; it is closed exactly once, when the last clone is dropped.
+#[derive(Clone)]
+struct Handle(Arc<HandleInner>);
+
+impl Handle {
+    fn raw(&self) -> HANDLE {
+        (self.0).0
+    }
+}
+
+fn get_process_handler(pid: Pid) -> Option<Handle> {
     if pid == 0 {
         return None;
     }
-    let options = PROCESS_QUERY_INFORMATION | PROCESS_VM_READ | PROCESS_TERMINATE;
-    let process_handler = unsafe { OpenProcess(options, FALSE, pid as DWORD) };
-    if process_handler.is_null() {
+    unsafe {
+        let options = PROCESS_QUERY_INFORMATION | PROCESS_VM_READ | PROCESS_TERMINATE;
+        if let Ok(h) = OpenProcess(options, FALSE, pid as u32) {
+            return Some(Handle(Arc::new(HandleInner(h))));
+        }
         let options = PROCESS_QUERY_INFORMATION | PROCESS_VM_READ;
-        let process_handler = unsafe { OpenProcess(options, FALSE, pid as DWORD) };
-        if process_handler.is_null() {
-            None
-        } else {
-            Some(process_handler)
+        if let Ok(h) = OpenProcess(options, FALSE, pid as u32) {
+            return Some(Handle(Arc::new(HandleInner(h))));
         }
-    } else {
-        Some(process_handler)
     }
+    None
 }
 
 /// Struct containing a process' information.
@@ -82,8 +316,6 @@ pub struct Process {
     /// pid of the processus
     pub pid: Pid,
     /// Environment of the process.
-    ///
-    /// Always empty except for current process.
     pub environ: Vec<String>,
     /// current working directory
     pub cwd: String,
@@ -95,7 +327,8 @@ pub struct Process {
     pub parent: Option<Pid>,
     /// Status of the Process.
     pub status: ProcessStatus,
-    handle: HANDLE,
+    priority: ProcessPriority,
+    handle: Option<Handle>,
     old_cpu: u64,
     old_sys_cpu: u64,
     old_user_cpu: u64,
@@ -107,21 +340,22 @@ pub struct Process {
 
 impl ProcessExt for Process {
     fn new(pid: Pid, parent: Option<Pid>, _: u64) -> Process {
-        if let Some(process_handler) = get_process_handler(pid) {
-            let mut h_mod = ::std::ptr::null_mut();
-            let mut process_name = [0u16; MAX_PATH + 1];
+        if let Some(handle) = get_process_handler(pid) {
+            let raw = handle.raw();
+            let mut h_mod = HMODULE::default();
+            let mut process_name = [0u16; MAX_PATH as usize + 1];
             let mut cb_needed = 0;
 
             unsafe {
-                if EnumProcessModulesEx(process_handler,
-                                        &mut h_mod,
-                                        ::std::mem::size_of::<DWORD>() as DWORD,
-                                        &mut cb_needed,
-                                        LIST_MODULES_ALL) != 0 {
-                    GetModuleBaseNameW(process_handler,
-                                       h_mod,
-                                       process_name.as_mut_ptr(),
-                                       MAX_PATH as DWORD + 1);
+                if K32EnumProcessModulesEx(raw,
+                                           &mut h_mod,
+                                           size_of::<HMODULE>() as u32,
+                                           &mut cb_needed,
+                                           LIST_MODULES_ALL).as_bool() {
+                    K32GetModuleBaseNameW(raw,
+                                          h_mod,
+                                          PWSTR(process_name.as_mut_ptr()),
+                                          MAX_PATH + 1);
                 }
                 let mut pos = 0;
                 for x in process_name.iter() {
@@ -131,29 +365,30 @@ impl ProcessExt for Process {
                     pos += 1;
                 }
                 let name = String::from_utf16_lossy(&process_name[..pos]);
-                let environ = get_proc_env(process_handler, pid as u32, &name);
+                let environ = get_proc_env(raw, pid as u32, &name);
+                let (exe, cwd, root) = get_exe_cwd_root(raw);
                 Process {
-                    handle: process_handler,
                     name: name,
                     pid: pid,
                     parent: parent,
-                    cmd: get_cmd_line(process_handler),
+                    cmd: get_cmd_line(raw),
                     environ: environ,
-                    exe: String::new(),
-                    cwd: String::new(),
-                    root: String::new(),
-                    status: ProcessStatus::Run,
+                    exe: exe,
+                    cwd: cwd,
+                    root: root,
+                    status: get_process_status(raw),
+                    priority: get_process_priority(raw),
                     memory: 0,
                     cpu_usage: 0.,
                     old_cpu: 0,
                     old_sys_cpu: 0,
                     old_user_cpu: 0,
-                    start_time: get_start_time(process_handler),
+                    start_time: get_start_time(raw),
+                    handle: Some(handle),
                 }
             }
         } else {
             Process {
-                handle: ::std::ptr::null_mut(),
                 name: String::new(),
                 pid: pid,
                 parent: parent,
@@ -162,32 +397,41 @@ impl ProcessExt for Process {
                 exe: String::new(),
                 cwd: String::new(),
                 root: String::new(),
-                status: ProcessStatus::Run,
+                status: ProcessStatus::Unknown,
+                priority: ProcessPriority::Unknown,
                 memory: 0,
                 cpu_usage: 0.,
                 old_cpu: 0,
                 old_sys_cpu: 0,
                 old_user_cpu: 0,
                 start_time: 0,
+                handle: None,
             }
         }
     }
 
     fn kill(&self, signal: ::Signal) -> bool {
-        let x = unsafe { TerminateProcess(self.handle, signal as c_uint) };
-        println!("{:?} {:?} {:x}", self.handle, signal as c_uint, x);
-        x != 0
+        match self.handle {
+            Some(ref handle) => unsafe { TerminateProcess(handle.raw(), signal as u32).is_ok() },
+            None => false,
+        }
     }
 }
 
-impl Drop for Process {
-    fn drop(&mut self) {
-        unsafe {
-            if self.handle.is_null() {
-                return
-            }
-            CloseHandle(self.handle);
-        }
+impl Process {
+    /// Returns the time of process launch as a local `DateTime`, handy for
+    /// displaying a process' age. Falls back to the Unix epoch if the stored
+    /// timestamp cannot be represented in the local time zone.
+    pub fn start_time_as_datetime(&self) -> DateTime<Local> {
+        Local
+            .timestamp_opt(self.start_time as i64, 0)
+            .single()
+            .unwrap_or_else(|| Local.timestamp_opt(0, 0).unwrap())
+    }
+
+    /// Returns the scheduling priority class this process runs at.
+    pub fn priority(&self) -> ProcessPriority {
+        self.priority
     }
 }
 
@@ -211,196 +455,388 @@ impl Debug for Process {
     }
 }
 
+/// Number of 100-nanosecond ticks between the Windows (1601) and Unix (1970)
+/// epochs.
+const WINDOWS_EPOCH_OFFSET: u64 = 116_444_736_000_000_000;
+
+/// Reinterprets a `FILETIME` as the 64-bit tick count it actually is.
+fn filetime_to_u64(ft: FILETIME) -> u64 {
+    ((ft.dwHighDateTime as u64) << 32) | (ft.dwLowDateTime as u64)
+}
+
 unsafe fn get_start_time(handle: HANDLE) -> u64 {
-    let mut start = 0u64;
     let mut fstart = zeroed();
     let mut x = zeroed();
 
-    GetProcessTimes(handle,
-                    &mut fstart as *mut FILETIME,
-                    &mut x as *mut FILETIME,
-                    &mut x as *mut FILETIME,
-                    &mut x as *mut FILETIME);
-    memcpy(&mut start as *mut u64 as *mut c_void,
-           &mut fstart as *mut FILETIME as *mut c_void,
-           size_of::<FILETIME>());
-    start
+    let _ = GetProcessTimes(handle, &mut fstart, &mut x, &mut x, &mut x);
+    let ticks = filetime_to_u64(fstart);
+    // `FILETIME` counts 100ns intervals since 1601; report seconds since the
+    // Unix epoch to match the Unix backends.
+    if ticks < WINDOWS_EPOCH_OFFSET {
+        return 0;
+    }
+    (ticks - WINDOWS_EPOCH_OFFSET) / 10_000_000
 }
 
 pub unsafe fn get_parent_process_id(pid: Pid) -> Option<Pid> {
-    let snapshot = CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0);
+    let snapshot = match CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0) {
+        Ok(snapshot) => snapshot,
+        Err(_) => return None,
+    };
     let mut entry: PROCESSENTRY32 = zeroed();
     entry.dwSize = size_of::<PROCESSENTRY32>() as u32;
-    let mut not_the_end = Process32First(snapshot, &mut entry);
-    while not_the_end != 0 {
+    let mut not_the_end = Process32First(snapshot, &mut entry).is_ok();
+    while not_the_end {
         if pid == entry.th32ProcessID as usize {
             // TODO: if some day I have the motivation to add threads:
             // ListProcessThreads(entry.th32ProcessID);
-            CloseHandle(snapshot);
+            let _ = CloseHandle(snapshot);
             return Some(entry.th32ParentProcessID as usize);
         }
-        not_the_end = Process32Next(snapshot, &mut entry);
+        not_the_end = Process32Next(snapshot, &mut entry).is_ok();
     }
-    CloseHandle(snapshot);
+    let _ = CloseHandle(snapshot);
     None
 }
 
-unsafe fn get_cmd_line(_handle: HANDLE) -> String {
-    /*let mut pinfo: ffi::PROCESS_BASIC_INFORMATION = ::std::mem::zeroed();
-    if ffi::NtQueryInformationProcess(handle,
-                                           0, // ProcessBasicInformation
-                                           &mut pinfo,
-                                           size_of::<ffi::PROCESS_BASIC_INFORMATION>(),
-                                           ::std::ptr::null_mut()) <= 0x7FFFFFFF {
-        return String::new();
+/// Reads `size` bytes at `addr` from the address space of `handle`.
+///
+/// Returns the bytes actually read, or `None` if the call fails (e.g. the
+/// handle lacks `PROCESS_VM_READ` or the page is not mapped).
+unsafe fn read_process_memory(handle: HANDLE, addr: *const c_void, size: usize) -> Option<Vec<u8>> {
+    if addr.is_null() || size == 0 {
+        return None;
     }
-    let ppeb: ffi::PPEB = pinfo.PebBaseAddress;
-    let mut ppeb_copy: ffi::PEB = ::std::mem::zeroed();
-    if kernel32::ReadProcessMemory(handle,
-                                   ppeb as *mut raw::c_void,
-                                   &mut ppeb_copy as *mut ffi::PEB as *mut raw::c_void,
-                                   size_of::<ffi::PPEB>() as SIZE_T,
-                                   ::std::ptr::null_mut()) != TRUE {
-        return String::new();
+    let mut buffer: Vec<u8> = vec![0; size];
+    let mut read: usize = 0;
+    if ReadProcessMemory(handle,
+                         addr,
+                         buffer.as_mut_ptr() as *mut c_void,
+                         size,
+                         Some(&mut read)).is_err() {
+        return None;
     }
+    buffer.truncate(read);
+    Some(buffer)
+}
 
-    let proc_param: ffi::PRTL_USER_PROCESS_PARAMETERS = ppeb_copy.ProcessParameters;
-    let rtl_proc_param_copy: ffi::RTL_USER_PROCESS_PARAMETERS = ::std::mem::zeroed();
-    if kernel32::ReadProcessMemory(handle,
-                                   proc_param as *mut ffi::PRTL_USER_PROCESS_PARAMETERS *mut raw::c_void,
-                                   &mut rtl_proc_param_copy as *mut ffi::RTL_USER_PROCESS_PARAMETERS as *mut raw::c_void,
-                                   size_of::<ffi::RTL_USER_PROCESS_PARAMETERS>() as SIZE_T,
-                                   ::std::ptr::null_mut()) != TRUE {
+/// A `UNICODE_STRING` whose `Buffer` still points into the *target* process.
+/// Normalised to a 64-bit address so the native and WOW64 layouts share a
+/// single reading routine.
+struct RemoteUString {
+    length: u16,
+    buffer: u64,
+}
+
+/// The subset of `RTL_USER_PROCESS_PARAMETERS` we consume, already widened to
+/// 64-bit addresses regardless of the target's bitness.
+struct ProcessParameters {
+    cmd_line: RemoteUString,
+    image_path: RemoteUString,
+    cwd: RemoteUString,
+    environment: u64,
+    environment_size: usize,
+}
+
+/// Follows a remote `UNICODE_STRING` and decodes it into an owned `String`.
+unsafe fn read_remote_unicode_string(handle: HANDLE, s: &RemoteUString) -> String {
+    if s.buffer == 0 || s.length == 0 {
         return String::new();
     }
-    let len: usize = rtl_proc_param_copy.CommandLine.Length as usize;
-    let mut buffer_copy: Vec<u8> = Vec::with_capacity(len);
-    buffer_copy.set_len(len);
-    if kernel32::ReadProcessMemory(handle,
-                                   rtl_proc_param_copy.CommandLine.Buffer as *mut raw::c_void,
-                                   buffer_copy.as_mut_ptr() as *mut raw::c_void,
-                                   len as SIZE_T,
-                                   ::std::ptr::null_mut()) == TRUE {
-        println!("{:?}", str::from_utf8_unchecked(buffer_copy.as_slice()));
-        str::from_utf8_unchecked(buffer_copy.as_slice()).to_owned()
+    match read_process_memory(handle, s.buffer as *const c_void, s.length as usize) {
+        Some(bytes) => {
+            let units = ::std::slice::from_raw_parts(bytes.as_ptr() as *const u16, bytes.len() / 2);
+            String::from_utf16_lossy(units)
+        }
+        None => String::new(),
+    }
+}
+
+/// Returns the `PEB32` base address when `handle` refers to a 32-bit process
+/// running under WOW64, or `None` for a native process. A non-null pointer from
+/// `ProcessWow64Information` is the WOW64 marker.
+unsafe fn wow64_peb_address(handle: HANDLE) -> Option<u64> {
+    let mut peb32: usize = 0;
+    let mut ret_len = 0;
+    if ffi::NtQueryInformationProcess(handle,
+                                      ffi::PROCESS_WOW64_INFORMATION,
+                                      &mut peb32 as *mut _ as *mut c_void,
+                                      size_of::<usize>() as u32,
+                                      &mut ret_len) != STATUS_SUCCESS {
+        return None;
+    }
+    if peb32 == 0 {
+        None
     } else {
-        String::new()
-    }*/
-    String::new()
-}
-
-unsafe fn get_proc_env(_handle: HANDLE, _pid: u32, _name: &str) -> Vec<String> {
-    let ret = Vec::new();
-    /*
-    println!("current pid: {}", kernel32::GetCurrentProcessId());
-    if kernel32::GetCurrentProcessId() == pid {
-        println!("current proc!");
-        for (key, value) in env::vars() {
-            ret.push(format!("{}={}", key, value));
+        Some(peb32 as u64)
+    }
+}
+
+/// Reads the process parameters of a native (same-bitness) process.
+unsafe fn get_process_parameters_native(handle: HANDLE) -> Option<ProcessParameters> {
+    let mut pbi: ffi::PROCESS_BASIC_INFORMATION = zeroed();
+    let mut ret_len = 0;
+    if ffi::NtQueryInformationProcess(handle,
+                                      ffi::PROCESS_BASIC_INFORMATION,
+                                      &mut pbi as *mut _ as *mut c_void,
+                                      size_of::<ffi::PROCESS_BASIC_INFORMATION>() as u32,
+                                      &mut ret_len) != STATUS_SUCCESS {
+        return None;
+    }
+    let peb_bytes = read_process_memory(handle,
+                                        pbi.PebBaseAddress as *const c_void,
+                                        size_of::<ffi::PEB>())?;
+    let peb = &*(peb_bytes.as_ptr() as *const ffi::PEB);
+    let params_bytes = read_process_memory(handle,
+                                           peb.ProcessParameters as *const c_void,
+                                           size_of::<ffi::RTL_USER_PROCESS_PARAMETERS>())?;
+    let params = &*(params_bytes.as_ptr() as *const ffi::RTL_USER_PROCESS_PARAMETERS);
+    Some(ProcessParameters {
+        cmd_line: RemoteUString {
+            length: params.CommandLine.Length,
+            buffer: params.CommandLine.Buffer.0 as u64,
+        },
+        image_path: RemoteUString {
+            length: params.ImagePathName.Length,
+            buffer: params.ImagePathName.Buffer.0 as u64,
+        },
+        cwd: RemoteUString {
+            length: params.CurrentDirectory.DosPath.Length,
+            buffer: params.CurrentDirectory.DosPath.Buffer.0 as u64,
+        },
+        environment: params.Environment as u64,
+        environment_size: params.EnvironmentSize,
+    })
+}
+
+/// Reads the process parameters of a 32-bit process through its `PEB32`.
+unsafe fn get_process_parameters_wow64(handle: HANDLE, peb32_addr: u64) -> Option<ProcessParameters> {
+    let peb_bytes = read_process_memory(handle,
+                                        peb32_addr as *const c_void,
+                                        size_of::<ffi::PEB32>())?;
+    let peb = &*(peb_bytes.as_ptr() as *const ffi::PEB32);
+    let params_bytes = read_process_memory(handle,
+                                           peb.ProcessParameters as u64 as *const c_void,
+                                           size_of::<ffi::RTL_USER_PROCESS_PARAMETERS32>())?;
+    let params = &*(params_bytes.as_ptr() as *const ffi::RTL_USER_PROCESS_PARAMETERS32);
+    Some(ProcessParameters {
+        cmd_line: RemoteUString {
+            length: params.CommandLine.Length,
+            buffer: params.CommandLine.Buffer as u64,
+        },
+        image_path: RemoteUString {
+            length: params.ImagePathName.Length,
+            buffer: params.ImagePathName.Buffer as u64,
+        },
+        cwd: RemoteUString {
+            length: params.CurrentDirectory.DosPath.Length,
+            buffer: params.CurrentDirectory.DosPath.Buffer as u64,
+        },
+        environment: params.Environment as u64,
+        environment_size: params.EnvironmentSize as usize,
+    })
+}
+
+/// Reads the process parameters, transparently picking the native or WOW64
+/// layout so cross-bitness enumeration works on a standard 64-bit host.
+unsafe fn get_process_parameters(handle: HANDLE) -> Option<ProcessParameters> {
+    match wow64_peb_address(handle) {
+        Some(peb32_addr) => get_process_parameters_wow64(handle, peb32_addr),
+        None => get_process_parameters_native(handle),
+    }
+}
+
+/// Asks the kernel directly for the command line. Only available on Windows
+/// 8.1+; returns `None` on older systems so the caller can fall back to walking
+/// the PEB manually.
+unsafe fn get_cmd_line_from_kernel(handle: HANDLE) -> Option<String> {
+    let mut len: u32 = 0;
+    // First call discovers the required buffer size.
+    let status = ffi::NtQueryInformationProcess(handle,
+                                                ffi::PROCESS_COMMAND_LINE_INFORMATION,
+                                                ::std::ptr::null_mut(),
+                                                0,
+                                                &mut len);
+    if status != STATUS_BUFFER_OVERFLOW && status != STATUS_INFO_LENGTH_MISMATCH {
+        return None;
+    }
+    let mut buffer: Vec<u8> = vec![0; len as usize];
+    if ffi::NtQueryInformationProcess(handle,
+                                      ffi::PROCESS_COMMAND_LINE_INFORMATION,
+                                      buffer.as_mut_ptr() as *mut c_void,
+                                      len,
+                                      &mut len) != STATUS_SUCCESS {
+        return None;
+    }
+    // The result is a `UNICODE_STRING` whose `Buffer` points just past the
+    // struct, inside the very buffer we just filled in our own address space.
+    let ustr = &*(buffer.as_ptr() as *const UNICODE_STRING);
+    if ustr.Buffer.is_null() || ustr.Length == 0 {
+        return Some(String::new());
+    }
+    let units = ::std::slice::from_raw_parts(ustr.Buffer.0, ustr.Length as usize / 2);
+    Some(String::from_utf16_lossy(units))
+}
+
+unsafe fn get_cmd_line(handle: HANDLE) -> String {
+    if let Some(cmd) = get_cmd_line_from_kernel(handle) {
+        return cmd;
+    }
+    match get_process_parameters(handle) {
+        Some(params) => read_remote_unicode_string(handle, &params.cmd_line),
+        None => String::new(),
+    }
+}
+
+/// Derives the root (install) directory of a process from its executable path
+/// by dropping the last `\`-separated component.
+fn root_dir(exe: &str) -> String {
+    match exe.rfind('\\') {
+        Some(pos) => exe[..pos].to_owned(),
+        None => String::new(),
+    }
+}
+
+/// Reads the executable path, current working directory and root directory of a
+/// process, reusing the same PEB plumbing as the command line.
+unsafe fn get_exe_cwd_root(handle: HANDLE) -> (String, String, String) {
+    match get_process_parameters(handle) {
+        Some(params) => {
+            let exe = read_remote_unicode_string(handle, &params.image_path);
+            let cwd = read_remote_unicode_string(handle, &params.cwd);
+            let root = root_dir(&exe);
+            (exe, cwd, root)
         }
-        return ret;
-    }
-    println!("1");
-    let snapshot_handle = kernel32::CreateToolhelp32Snapshot(TH32CS_SNAPTHREAD, 0);
-    if !snapshot_handle.is_null() {
-        println!("2");
-        let mut target_thread: THREADENTRY32 = zeroed();
-        target_thread.dwSize = size_of::<THREADENTRY32>() as DWORD;
-        if kernel32::Thread32First(snapshot_handle, &mut target_thread) == TRUE {
-            println!("3");
-            loop {
-                if target_thread.th32OwnerProcessID == pid {
-                    println!("4");
-                    let thread_handle = kernel32::OpenThread(THREAD_SUSPEND_RESUME | THREAD_QUERY_INFORMATION | THREAD_GET_CONTEXT,
-                                                             FALSE,
-                                                             target_thread.th32ThreadID);
-                    if !thread_handle.is_null() {
-                        println!("5 -> {}", pid);
-                        if kernel32::SuspendThread(thread_handle) != DWORD::max_value() {
-                            println!("6");
-                            let mut context = zeroed();
-                            if kernel32::GetThreadContext(thread_handle, &mut context) != 0 {
-                                println!("7 --> {:?}", context);
-                                let mut x = vec![0u8; 10];
-                                if kernel32::ReadProcessMemory(handle,
-                                                               context.MxCsr as usize as *mut winapi::c_void,
-                                                               x.as_mut_ptr() as *mut winapi::c_void,
-                                                               x.len() as u64,
-                                                               ::std::ptr::null_mut()) != 0 {
-                                    for y in x {
-                                        print!("{}", y as char);
-                                    }
-                                    println!("");
-                                } else {
-                                    println!("failure... {:?}", kernel32::GetLastError());
-                                }
-                            } else {
-                                println!("-> {:?}", kernel32::GetLastError());
-                            }
-                            kernel32::ResumeThread(thread_handle);
-                        }
-                        kernel32::CloseHandle(thread_handle);
-                    }
-                    break;
-                }
-                if kernel32::Thread32Next(snapshot_handle, &mut target_thread) != TRUE {
-                    break;
-                }
-            }
+        None => (String::new(), String::new(), String::new()),
+    }
+}
+
+/// Splits a raw environment block (a run of null-terminated UTF-16 `KEY=VALUE`
+/// strings ended by a double null) into owned `String`s.
+fn parse_environ(block: &[u16]) -> Vec<String> {
+    let mut ret = Vec::new();
+    for entry in block.split(|c| *c == 0) {
+        if entry.is_empty() {
+            // Either the trailing empty slice or the final double null.
+            continue;
         }
-        kernel32::CloseHandle(snapshot_handle);
-    }*/
+        ret.push(String::from_utf16_lossy(entry));
+    }
     ret
 }
 
+unsafe fn get_proc_env(handle: HANDLE, pid: u32, _name: &str) -> Vec<String> {
+    // The current process' block is far cheaper to read through the std API,
+    // and avoids opening a second view on ourselves.
+    if GetCurrentProcessId() == pid {
+        return ::std::env::vars().map(|(k, v)| format!("{}={}", k, v)).collect();
+    }
+    let params = match get_process_parameters(handle) {
+        Some(params) => params,
+        None => return Vec::new(),
+    };
+    if params.environment == 0 || params.environment_size == 0 {
+        return Vec::new();
+    }
+    match read_process_memory(handle, params.environment as *const c_void, params.environment_size) {
+        Some(bytes) => {
+            let units = ::std::slice::from_raw_parts(bytes.as_ptr() as *const u16, bytes.len() / 2);
+            parse_environ(units)
+        }
+        None => Vec::new(),
+    }
+}
+
 pub fn compute_cpu_usage(p: &mut Process, nb_processors: u64) {
+    let handle = match p.handle {
+        Some(ref handle) => handle.raw(),
+        None => return,
+    };
     unsafe {
-        let mut now: ULARGE_INTEGER = ::std::mem::zeroed();
-        let mut sys: ULARGE_INTEGER = ::std::mem::zeroed();
-        let mut user: ULARGE_INTEGER = ::std::mem::zeroed();
-        let mut ftime: FILETIME = zeroed();
         let mut fsys: FILETIME = zeroed();
         let mut fuser: FILETIME = zeroed();
+        let mut fcreate: FILETIME = zeroed();
+        let mut fexit: FILETIME = zeroed();
+
+        let ftime = GetSystemTimeAsFileTime();
+        let now = filetime_to_u64(ftime);
+
+        let _ = GetProcessTimes(handle, &mut fcreate, &mut fexit, &mut fsys, &mut fuser);
+        let sys = filetime_to_u64(fsys);
+        let user = filetime_to_u64(fuser);
 
-        GetSystemTimeAsFileTime(&mut ftime);
-        memcpy(&mut now as *mut ULARGE_INTEGER as *mut c_void,
-               &mut ftime as *mut FILETIME as *mut c_void,
-               size_of::<FILETIME>());
-
-        GetProcessTimes(p.handle,
-                        &mut ftime as *mut FILETIME,
-                        &mut ftime as *mut FILETIME,
-                        &mut fsys as *mut FILETIME,
-                        &mut fuser as *mut FILETIME);
-        memcpy(&mut sys as *mut ULARGE_INTEGER as *mut c_void,
-               &mut fsys as *mut FILETIME as *mut c_void,
-               size_of::<FILETIME>());
-        memcpy(&mut user as *mut ULARGE_INTEGER as *mut c_void,
-               &mut fuser as *mut FILETIME as *mut c_void,
-               size_of::<FILETIME>());
-        p.cpu_usage = ((*sys.QuadPart() - p.old_sys_cpu) as f32 + (*user.QuadPart() - p.old_user_cpu) as f32)
-            / (*now.QuadPart() - p.old_cpu) as f32 / nb_processors as f32 * 100.;
-        p.old_cpu = *now.QuadPart();
-        p.old_user_cpu = *user.QuadPart();
-        p.old_sys_cpu = *sys.QuadPart();
+        p.cpu_usage = ((sys - p.old_sys_cpu) as f32 + (user - p.old_user_cpu) as f32)
+            / (now - p.old_cpu) as f32 / nb_processors as f32 * 100.;
+        p.old_cpu = now;
+        p.old_user_cpu = user;
+        p.old_sys_cpu = sys;
     }
 }
 
 pub fn get_handle(p: &Process) -> HANDLE {
-    p.handle
+    match p.handle {
+        Some(ref handle) => handle.raw(),
+        None => HANDLE::default(),
+    }
+}
+
+/// Reports whether the process is still running, has exited (but is still
+/// referenced by our handle), or cannot be queried.
+///
+/// Note: this does not surface a *suspended* state. `GetExitCodeProcess` only
+/// distinguishes "still active" from "exited", so a process whose threads are
+/// all suspended is still reported as [`ProcessStatus::Run`]; detecting
+/// suspension would require enumerating thread states. Conversely, a process
+/// that legitimately exits with code `STILL_ACTIVE` (259) is indistinguishable
+/// from a live one and is therefore misclassified as [`ProcessStatus::Run`].
+unsafe fn get_process_status(handle: HANDLE) -> ProcessStatus {
+    let mut exit_code: u32 = 0;
+    if GetExitCodeProcess(handle, &mut exit_code).is_err() {
+        return ProcessStatus::Unknown;
+    }
+    if exit_code == STILL_ACTIVE.0 as u32 {
+        ProcessStatus::Run
+    } else {
+        ProcessStatus::Zombie
+    }
+}
+
+/// Maps the `GetPriorityClass` result onto `ProcessPriority`.
+unsafe fn get_process_priority(handle: HANDLE) -> ProcessPriority {
+    match PROCESS_CREATION_FLAGS(GetPriorityClass(handle)) {
+        IDLE_PRIORITY_CLASS => ProcessPriority::Idle,
+        BELOW_NORMAL_PRIORITY_CLASS => ProcessPriority::BelowNormal,
+        NORMAL_PRIORITY_CLASS => ProcessPriority::Normal,
+        ABOVE_NORMAL_PRIORITY_CLASS => ProcessPriority::AboveNormal,
+        HIGH_PRIORITY_CLASS => ProcessPriority::High,
+        REALTIME_PRIORITY_CLASS => ProcessPriority::RealTime,
+        _ => ProcessPriority::Unknown,
+    }
 }
 
 pub fn update_proc_info(p: &mut Process) {
     update_memory(p);
+    let handle = match p.handle {
+        Some(ref handle) => handle.raw(),
+        None => return,
+    };
+    unsafe {
+        p.status = get_process_status(handle);
+        p.priority = get_process_priority(handle);
+    }
 }
 
 pub fn update_memory(p: &mut Process) {
+    let handle = match p.handle {
+        Some(ref handle) => handle.raw(),
+        None => return,
+    };
     unsafe {
         let mut pmc: PROCESS_MEMORY_COUNTERS_EX = zeroed();
-        if GetProcessMemoryInfo(p.handle,
-                                &mut pmc as *mut PROCESS_MEMORY_COUNTERS_EX as *mut c_void as *mut PROCESS_MEMORY_COUNTERS,
-                                size_of::<PROCESS_MEMORY_COUNTERS_EX>() as DWORD) != 0 {
+        if K32GetProcessMemoryInfo(handle,
+                                   &mut pmc as *mut PROCESS_MEMORY_COUNTERS_EX as *mut PROCESS_MEMORY_COUNTERS,
+                                   size_of::<PROCESS_MEMORY_COUNTERS_EX>() as u32).as_bool() {
             p.memory = (pmc.PrivateUsage as u64) >> 10u64; // / 1024;
         }
     }
-}
\ No newline at end of file
+}